@@ -1,40 +1,174 @@
 extern crate actix;
 extern crate actix_web;
+extern crate brotli;
+extern crate clap;
 extern crate elasticlunr;
 extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
+extern crate flate2;
 extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate mdbook;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
 #[macro_use]
 extern crate tantivy;
-extern crate tempdir;
+extern crate zstd;
 
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 use actix::prelude::*;
+use actix_web::dev::Body;
+use actix_web::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH};
+use actix_web::http::StatusCode;
 use actix_web::{fs, middleware, Application, AsyncResponder, HttpRequest, HttpResponse,
                 HttpServer, Method};
+use clap::{App, Arg};
 use elasticlunr::document_store::DocumentStore;
-use futures::{Future, IntoFuture};
+use futures::{Future, IntoFuture, Stream};
 use mdbook::MDBook;
 use std::fs::File;
-use std::io::Read;
-use std::path::Path;
-use tantivy::collector::TopCollector;
-use tantivy::query::{Query, QueryParser};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tantivy::collector::{chain, CountCollector, TopCollector};
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, TermQuery};
 use tantivy::schema::*;
-use tempdir::TempDir;
+use tantivy::Document;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: &str = "8080";
+const DEFAULT_BOOKSHELF_ROOT: &str = "..";
+const DEFAULT_INDEX_DIR: &str = "bookshelf_index";
+const DEFAULT_SEARCHERS: &str = "8";
+const DEFAULT_BOOKS: &[&str] = &[
+    "book/first-edition",
+    "book/second-edition",
+    "nomicon",
+    "rust-by-example",
+];
+
+struct Config {
+    host: String,
+    port: String,
+    bookshelf_root: String,
+    index_dir: String,
+    searchers: usize,
+    books: Vec<String>,
+}
+
+fn parse_args() -> Result<Config> {
+    let matches = App::new("global-search")
+        .arg(Arg::with_name("host").long("host").takes_value(true))
+        .arg(Arg::with_name("port").long("port").takes_value(true))
+        .arg(
+            Arg::with_name("bookshelf-root")
+                .long("bookshelf-root")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("index-dir")
+                .long("index-dir")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("searchers")
+                .long("searchers")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("book")
+                .long("book")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .get_matches();
+
+    let books = match matches.values_of("book") {
+        Some(values) => values.map(String::from).collect(),
+        None => DEFAULT_BOOKS.iter().map(|&b| b.to_string()).collect(),
+    };
+
+    Ok(Config {
+        host: matches.value_of("host").unwrap_or(DEFAULT_HOST).to_string(),
+        port: matches.value_of("port").unwrap_or(DEFAULT_PORT).to_string(),
+        bookshelf_root: matches
+            .value_of("bookshelf-root")
+            .unwrap_or(DEFAULT_BOOKSHELF_ROOT)
+            .to_string(),
+        index_dir: matches
+            .value_of("index-dir")
+            .unwrap_or(DEFAULT_INDEX_DIR)
+            .to_string(),
+        searchers: matches
+            .value_of("searchers")
+            .unwrap_or(DEFAULT_SEARCHERS)
+            .parse()
+            .chain_err(|| "--searchers must be a number")?,
+        books,
+    })
+}
+
+// file_count catches deletions, which can't move latest_mtime backwards.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct BookFingerprint {
+    file_count: u64,
+    latest_mtime: u64,
+}
+
+fn load_book_fingerprints(meta_path: &Path) -> HashMap<String, BookFingerprint> {
+    File::open(meta_path)
+        .ok()
+        .and_then(|mut f| {
+            let mut contents = String::new();
+            f.read_to_string(&mut contents).ok()?;
+            serde_json::from_str(&contents).ok()
+        })
+        .unwrap_or_default()
+}
+
+// Fingerprint the sources before build(); searchindex.js is always
+// freshly written and so can't tell us whether they actually changed.
+fn fingerprint_sources(dir: &Path) -> Result<BookFingerprint> {
+    let mut file_count = 0;
+    let mut latest_mtime = 0;
+    for entry in ::std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let nested = fingerprint_sources(&entry.path())?;
+            file_count += nested.file_count;
+            latest_mtime = latest_mtime.max(nested.latest_mtime);
+        } else {
+            file_count += 1;
+            let mtime = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            latest_mtime = latest_mtime.max(mtime);
+        }
+    }
+    Ok(BookFingerprint {
+        file_count,
+        latest_mtime,
+    })
+}
 
 fn run() -> Result<()> {
     env_logger::Builder::from_env("SEARCH_LOG")
         .filter(Some("global_search"), log::LevelFilter::Info)
         .init();
 
+    let config = parse_args()?;
+
     let mut schema_builder = SchemaBuilder::default();
     let book = schema_builder.add_text_field("book", STRING | STORED);
     let section = schema_builder.add_text_field("section", STRING | STORED);
@@ -42,26 +176,54 @@ fn run() -> Result<()> {
     let breadcrumbs = schema_builder.add_text_field("breadcrumbs", TEXT | STORED);
     let body = schema_builder.add_text_field("body", TEXT | STORED);
     let schema = schema_builder.build();
-    let tmp_dir = TempDir::new("bookshelf_index")?;
-    let search_index = tantivy::Index::create(tmp_dir.path(), schema.clone())?;
+
+    let Config {
+        host,
+        port,
+        bookshelf_root,
+        index_dir,
+        searchers,
+        books,
+    } = config;
+    let root = PathBuf::from(bookshelf_root);
+    let index_dir = PathBuf::from(index_dir);
+
+    ::std::fs::create_dir_all(&index_dir)?;
+    let search_index = match tantivy::Index::open_in_dir(&index_dir) {
+        Ok(index) => {
+            info!("Opened existing index at {:?}", index_dir);
+            index
+        }
+        Err(_) => {
+            info!("No usable index at {:?}, creating one", index_dir);
+            tantivy::Index::create_in_dir(&index_dir, schema.clone())?
+        }
+    };
     let mut index_writer = search_index.writer(100_000_000)?;
 
-    let root = Path::new("..");
-    let books = [
-        "book/first-edition",
-        "book/second-edition",
-        "nomicon",
-        "rust-by-example",
-    ];
+    let meta_path = index_dir.join("book_fingerprints.json");
+    let mut book_fingerprints = load_book_fingerprints(&meta_path);
+
     for path in &books {
+        let book_dir = root.join(path);
+        let fingerprint = fingerprint_sources(&book_dir.join("src")).unwrap_or(BookFingerprint {
+            file_count: 0,
+            latest_mtime: 0,
+        });
+        if book_fingerprints.get(path) == Some(&fingerprint) {
+            info!("{} is unchanged, skipping build and reindex", path);
+            continue;
+        }
+
         {
-            let book = MDBook::load(&root.join(path))
+            let book = MDBook::load(&book_dir)
                 .map_err(|e| e.chain_err(|| format!("Error Building Book {}", path)))?;
             book.build()?;
         }
         info!("Built {}", path);
+        book_fingerprints.insert(path.to_string(), fingerprint);
 
-        let index_path = root.join(path).join("book").join("searchindex.js");
+        let index_path = book_dir.join("book").join("searchindex.js");
         info!("Loading document store from {:?}", index_path);
         let mut index = String::new();
         File::open(index_path)?.read_to_string(&mut index)?;
@@ -75,6 +237,11 @@ fn run() -> Result<()> {
             continue;
         }
 
+        // Drop any documents we previously indexed for this book before
+        // re-adding its current contents, so edits and deletions aren't
+        // left behind as stale hits.
+        index_writer.delete_term(Term::from_field_text(book, path));
+
         for (doc_ref, doc_fields) in &docstore.docs {
             index_writer.add_document(doc!(
                 book => path.to_string(),
@@ -89,22 +256,29 @@ fn run() -> Result<()> {
 
     index_writer.commit()?;
     search_index.load_searchers()?;
+    ::std::fs::write(&meta_path, serde_json::to_string(&book_fingerprints)?)?;
     info!("Search index ready");
 
     let sys = actix::System::new("global-search");
 
     // Should be less than NUM_SEARCHERS, currently 12
     let search_index = Arc::new(search_index);
-    let addr = SyncArbiter::start(8, move || QueryExecutor {
+    let addr = SyncArbiter::start(searchers, move || QueryExecutor {
         index: search_index.clone(),
         query_parser: QueryParser::for_index(&search_index, vec![title, breadcrumbs, body]),
-        collector: TopCollector::with_limit(10),
+        book_field: book,
+        section_field: section,
+        title_field: title,
+        breadcrumbs_field: breadcrumbs,
+        body_field: body,
     });
 
+    let bind_addr = format!("{}:{}", host, port);
     let _server = HttpServer::new(move || {
         let mut app = Application::with_state(AppState {
             searcher: addr.clone(),
         }).middleware(middleware::Logger::default())
+            .middleware(Compress)
             .resource("/search", |r| r.method(Method::GET).f(query));
         for path in &books {
             let mut url = String::from("/bookshelf/");
@@ -115,10 +289,10 @@ fn run() -> Result<()> {
             )
         }
         app
-    }).bind("127.0.0.1:8080")?
+    }).bind(&bind_addr)?
         .start();
 
-    info!("Listening on 127.1:8080");
+    info!("Listening on {}", bind_addr);
     sys.run();
 
     Ok(())
@@ -128,11 +302,128 @@ struct AppState {
     searcher: Addr<Syn, QueryExecutor>,
 }
 
+// Below this it isn't worth paying compression overhead.
+const MIN_COMPRESS_BYTES: usize = 860;
+
+// Content-negotiated compression (zstd/brotli/gzip), applied to every
+// resource and static handler hung off the Application.
+struct Compress;
+
+fn supports_any_codec(accept_encoding: &str) -> bool {
+    accept_encoding.contains("zstd") || accept_encoding.contains("br")
+        || accept_encoding.contains("gzip")
+}
+
+fn compress(accept_encoding: &str, body: &[u8]) -> Option<(&'static str, Vec<u8>)> {
+    if body.len() < MIN_COMPRESS_BYTES {
+        return None;
+    }
+    if accept_encoding.contains("zstd") {
+        zstd::encode_all(body, 0).ok().map(|bytes| ("zstd", bytes))
+    } else if accept_encoding.contains("br") {
+        let mut out = Vec::new();
+        let result = brotli::CompressorWriter::new(&mut out, 4096, 5, 22).write_all(body);
+        result.map(|_| ("br", out)).ok()
+    } else if accept_encoding.contains("gzip") {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(body)
+            .and_then(|_| encoder.finish())
+            .map(|bytes| ("gzip", bytes))
+            .ok()
+    } else {
+        None
+    }
+}
+
+fn finish_body(resp: &mut HttpResponse, accept_encoding: &str, body: Vec<u8>) {
+    // The old Content-Length no longer matches a rebuffered/compressed body;
+    // drop it and let the writer recompute it from the Binary body below.
+    resp.headers_mut().remove(CONTENT_LENGTH);
+    match compress(accept_encoding, &body) {
+        Some((encoding, compressed)) => {
+            resp.headers_mut()
+                .insert(CONTENT_ENCODING, encoding.parse().unwrap());
+            resp.set_body(compressed);
+        }
+        None => resp.set_body(body),
+    }
+}
+
+impl<S> middleware::Middleware<S> for Compress {
+    fn response(
+        &self,
+        req: &mut HttpRequest<S>,
+        mut resp: HttpResponse,
+    ) -> actix_web::Result<middleware::Response> {
+        if resp.headers().contains_key(CONTENT_ENCODING) {
+            return Ok(middleware::Response::Done(resp));
+        }
+        // A 206 is already framed around the uncompressed byte range requested.
+        if resp.status() == StatusCode::PARTIAL_CONTENT || !resp.status().is_success() {
+            return Ok(middleware::Response::Done(resp));
+        }
+        let accept_encoding = req.headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        if !supports_any_codec(&accept_encoding) {
+            return Ok(middleware::Response::Done(resp));
+        }
+
+        // StaticFiles/NamedFile serve through Body::Streaming, so collect it first.
+        match resp.replace_body(Body::Empty) {
+            Body::Binary(bin) => {
+                finish_body(&mut resp, &accept_encoding, bin.as_ref().to_vec());
+                Ok(middleware::Response::Done(resp))
+            }
+            Body::Streaming(stream) => {
+                let body_fut = stream
+                    .fold(Vec::new(), |mut acc, chunk| {
+                        acc.extend_from_slice(&chunk);
+                        Ok::<_, actix_web::Error>(acc)
+                    })
+                    .map(move |body| {
+                        finish_body(&mut resp, &accept_encoding, body);
+                        resp
+                    });
+                Ok(middleware::Response::Future(Box::new(body_fut)))
+            }
+            other => {
+                resp.set_body(other);
+                Ok(middleware::Response::Done(resp))
+            }
+        }
+    }
+}
+
+const DEFAULT_NHITS: usize = 10;
+const MAX_NHITS: usize = 100;
+
 fn query(req: HttpRequest<AppState>) -> Box<Future<Item = HttpResponse, Error = actix_web::Error>> {
     if let Some(query) = req.query().get("query").map(ToString::to_string) {
+        let nhits = req.query()
+            .get("nhits")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_NHITS)
+            .max(1)
+            .min(MAX_NHITS);
+        let offset = req.query()
+            .get("offset")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let book = req.query().get("book").map(ToString::to_string);
+        let section = req.query().get("section").map(ToString::to_string);
         req.state()
             .searcher
-            .send(SearchQuery { query })
+            .send(SearchQuery {
+                query,
+                nhits,
+                offset,
+                book,
+                section,
+            })
             .from_err()
             .and_then(|res| match res {
                 Ok(resp) => Ok(HttpResponse::Ok()
@@ -156,8 +447,36 @@ fn query(req: HttpRequest<AppState>) -> Box<Future<Item = HttpResponse, Error =
     }
 }
 
+#[derive(Serialize)]
+struct SearchHit {
+    book: String,
+    section: String,
+    title: String,
+    breadcrumbs: String,
+    body: String,
+    score: f32,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    total: usize,
+    took_ms: u64,
+    hits: Vec<SearchHit>,
+}
+
+fn field_text(doc: &Document, field: Field) -> String {
+    doc.get_first(field)
+        .and_then(Value::text)
+        .unwrap_or("")
+        .to_string()
+}
+
 struct SearchQuery {
     pub query: String,
+    pub nhits: usize,
+    pub offset: usize,
+    pub book: Option<String>,
+    pub section: Option<String>,
 }
 
 impl Message for SearchQuery {
@@ -167,7 +486,11 @@ impl Message for SearchQuery {
 struct QueryExecutor {
     pub index: Arc<tantivy::Index>,
     pub query_parser: QueryParser,
-    pub collector: TopCollector,
+    pub book_field: Field,
+    pub section_field: Field,
+    pub title_field: Field,
+    pub breadcrumbs_field: Field,
+    pub body_field: Field,
 }
 
 impl Actor for QueryExecutor {
@@ -178,28 +501,63 @@ impl Handler<SearchQuery> for QueryExecutor {
     type Result = Result<String>;
 
     fn handle(&mut self, msg: SearchQuery, _ctx: &mut Self::Context) -> Result<String> {
-        let query: Box<Query> = self.query_parser
+        let parsed_query: Box<Query> = self.query_parser
             .parse_query(&msg.query)
             .map_err(::tantivy::Error::from)?;
         let searcher = self.index.searcher();
-        let schema = self.index.schema();
-        searcher.search(&*query, &mut self.collector)?;
-
-        // Our top collector now contains the 10
-        // most relevant doc ids...
-        let mut response_body = String::from("[");
-        let doc_addresses = self.collector.docs();
-        for doc_address in doc_addresses {
-            let retrieved_doc = searcher.doc(&doc_address)?;
-            response_body.push_str(&schema.to_json(&retrieved_doc));
-            response_body.push(',');
-            trace!("Address: {:?}", doc_address);
-            trace!("Result: {}\n", schema.to_json(&retrieved_doc));
-        }
-        response_body.pop();
-        response_body.push_str("]\n");
-
-        Ok(response_body)
+
+        // Combine the user's free-text query with any book/section scoping
+        // as MUST clauses, so callers can restrict a search to one book
+        // without us having to maintain a separate per-book index.
+        let mut clauses: Vec<(Occur, Box<Query>)> = vec![(Occur::Must, parsed_query)];
+        if let Some(book) = msg.book {
+            let term = Term::from_field_text(self.book_field, &book);
+            clauses.push((Occur::Must, Box::new(TermQuery::new(term, IndexRecordOption::Basic))));
+        }
+        if let Some(section) = msg.section {
+            let term = Term::from_field_text(self.section_field, &section);
+            clauses.push((Occur::Must, Box::new(TermQuery::new(term, IndexRecordOption::Basic))));
+        }
+        let query: Box<Query> = Box::new(BooleanQuery::from(clauses));
+
+        // Fetch enough hits to cover the requested page, then slice it out,
+        // rather than hardcoding a single limit shared across every query.
+        // `nhits` is clamped to at least 1 (TopCollector::with_limit panics
+        // on 0) and the sum is saturating so a huge offset/nhits can't
+        // overflow or wrap into a bogus limit.
+        let limit = msg.offset.saturating_add(msg.nhits.max(1));
+        let mut top_collector = TopCollector::with_limit(limit);
+        let mut count_collector = CountCollector::default();
+        let started = Instant::now();
+        searcher.search(&*query, &mut chain().push(&mut top_collector).push(&mut count_collector))?;
+        let elapsed = started.elapsed();
+        let took_ms = elapsed.as_secs() * 1_000 + u64::from(elapsed.subsec_nanos()) / 1_000_000;
+
+        let hits = top_collector
+            .score_docs()
+            .into_iter()
+            .skip(msg.offset)
+            .take(msg.nhits)
+            .map(|(score, doc_address)| {
+                trace!("Address: {:?}", doc_address);
+                let retrieved_doc = searcher.doc(&doc_address)?;
+                Ok(SearchHit {
+                    book: field_text(&retrieved_doc, self.book_field),
+                    section: field_text(&retrieved_doc, self.section_field),
+                    title: field_text(&retrieved_doc, self.title_field),
+                    breadcrumbs: field_text(&retrieved_doc, self.breadcrumbs_field),
+                    body: field_text(&retrieved_doc, self.body_field),
+                    score,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let response = SearchResponse {
+            total: count_collector.count(),
+            took_ms,
+            hits,
+        };
+        Ok(serde_json::to_string(&response)? + "\n")
     }
 }
 